@@ -1,19 +1,114 @@
 #![no_std]
 
 extern crate libc;
+#[cfg(feature = "jemalloc")]
+extern crate jemalloc_sys;
 #[cfg(test)]
+#[macro_use]
 extern crate std;
 
-use core::ops::Deref;
+use core::mem;
+use core::ops::{Deref, DerefMut};
+use core::ptr;
 use core::slice;
 use core::str::{Utf8Error, self};
 use libc::{c_char, c_void};
 
-const DUMMY_PTR: *mut c_void = 0x1 as *mut c_void;
+const DUMMY_PTR: *mut c_void = ptr::dangling_mut::<c_void>();
+
+/// The allocator backend that `Malloc`'s allocating constructors and
+/// `Drop` go through, so a buffer from those constructors is always
+/// `free`'d with whatever allocated it. Selecting the `jemalloc` feature
+/// routes everything through `jemalloc_sys` instead of `libc`, including
+/// the `free` of pointers wrapped by `from_array`, `from_c_str` and
+/// `from_raw`, which must then come from the same backend.
+mod alloc {
+    use libc::{c_void, size_t};
+
+    #[cfg(not(feature = "jemalloc"))]
+    pub unsafe fn malloc(size: size_t) -> *mut c_void {
+        ::libc::malloc(size)
+    }
+
+    #[cfg(feature = "jemalloc")]
+    pub unsafe fn malloc(size: size_t) -> *mut c_void {
+        ::jemalloc_sys::malloc(size)
+    }
+
+    #[cfg(not(feature = "jemalloc"))]
+    pub unsafe fn memalign(alignment: size_t, size: size_t) -> *mut c_void {
+        ::libc::memalign(alignment, size)
+    }
+
+    #[cfg(feature = "jemalloc")]
+    pub unsafe fn memalign(alignment: size_t, size: size_t) -> *mut c_void {
+        ::jemalloc_sys::aligned_alloc(alignment, size)
+    }
+
+    #[cfg(not(feature = "jemalloc"))]
+    pub unsafe fn free(ptr: *mut c_void) {
+        ::libc::free(ptr)
+    }
+
+    #[cfg(feature = "jemalloc")]
+    pub unsafe fn free(ptr: *mut c_void) {
+        ::jemalloc_sys::free(ptr)
+    }
+}
 
 /// A type that represents a `malloc`'d chunk of memory.
 pub struct Malloc<T: ?Sized> {
     ptr: *mut T,
+    /// Whether `ptr` is a placeholder that was never handed out by the
+    /// allocator (e.g. `alloc_aligned`'s zero-size shortcut), recorded at
+    /// construction time so `Drop` knows not to `free` it. This must not be
+    /// re-derived from `ptr`'s address or `T`'s size, since a real
+    /// allocation can otherwise legitimately be zero-sized or low-addressed.
+    dangling: bool,
+}
+
+impl<T> Malloc<T> {
+    /// Constructs a new `Malloc` by `malloc`ing enough space for a `T` and
+    /// moving `value` into it.
+    ///
+    /// For a zero-sized `T` this skips `malloc` entirely, since `malloc(0)`
+    /// is allowed to return null and writing through that would be UB;
+    /// `ptr::dangling_mut::<T>()` is used instead, which (unlike the fixed
+    /// `DUMMY_PTR` sentinel) is guaranteed aligned for `T` no matter `T`'s
+    /// alignment.
+    pub fn new(value: T) -> Malloc<T> {
+        unsafe {
+            let dangling = mem::size_of::<T>() == 0;
+            let ptr = if dangling {
+                ptr::dangling_mut::<T>()
+            } else {
+                alloc::malloc(mem::size_of::<T>()) as *mut T
+            };
+            ptr::write(ptr, value);
+            Malloc { ptr, dangling }
+        }
+    }
+
+    /// Constructs a `Malloc<T>` that takes ownership of the `T` at `ptr`.
+    ///
+    /// When this `Malloc` drops, the `T` will be dropped in place and the
+    /// buffer will be `free`'d.
+    ///
+    /// Unsafe because `ptr` must point to a single, valid, `malloc`'d `T`.
+    pub unsafe fn from_raw(ptr: *mut T) -> Malloc<T> {
+        Malloc { ptr, dangling: false }
+    }
+
+    /// Consumes the `Malloc`, returning the wrapped pointer without running
+    /// `T`'s destructor or `free`ing the memory.
+    ///
+    /// The caller becomes responsible for the memory, e.g. by handing it to
+    /// C or by passing it back to `from_raw`.
+    pub fn into_raw(self) -> *mut T {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
 }
 
 impl<T: Copy> Malloc<[T]> {
@@ -26,14 +121,70 @@ impl<T: Copy> Malloc<[T]> {
     /// Unsafe because there must be `len` contiguous, valid instances of `T`
     /// at `ptr`.
     pub unsafe fn from_array(ptr: *mut T, len: usize) -> Option<Malloc<[T]>> {
-        let ptr = match (ptr.is_null(), len) {
+        let (ptr, dangling) = match (ptr.is_null(), len) {
             // Even a 0-size slice cannot be null, so just use another pointer
-            (true, 0) => DUMMY_PTR as *mut T,
+            (true, 0) => (DUMMY_PTR as *mut T, true),
             (true, _) => return None,
-            (false, _) => ptr,
+            (false, _) => (ptr, false),
         };
         let slice = slice::from_raw_parts(ptr, len);
-        Some(Malloc { ptr: slice as *const [T] as *mut [T] })
+        Some(Malloc { ptr: slice as *const [T] as *mut [T], dangling })
+    }
+
+    /// Allocates an uninitialized buffer of `len` elements through the
+    /// configured allocator backend (see the `jemalloc` feature), aligned
+    /// for `T`.
+    ///
+    /// The elements are not initialized; reading them before writing is
+    /// undefined behavior.
+    pub fn alloc_uninit(len: usize) -> Malloc<[T]> {
+        unsafe {
+            let (ptr, dangling) = Self::alloc_aligned(len);
+            let slice = slice::from_raw_parts(ptr, len);
+            Malloc { ptr: slice as *const [T] as *mut [T], dangling }
+        }
+    }
+
+    /// Allocates a zero-initialized buffer of `len` elements, aligned for
+    /// `T`.
+    pub fn zeroed(len: usize) -> Malloc<[T]> {
+        unsafe {
+            let (ptr, dangling) = Self::alloc_aligned(len);
+            ptr::write_bytes(ptr, 0, len);
+            let slice = slice::from_raw_parts(ptr, len);
+            Malloc { ptr: slice as *const [T] as *mut [T], dangling }
+        }
+    }
+
+    /// Allocates `len * size_of::<T>()` bytes aligned to `align_of::<T>()`,
+    /// since plain `malloc` isn't guaranteed to satisfy an over-aligned `T`
+    /// (e.g. SIMD types or cache-line-aligned structs).
+    ///
+    /// With the `zst_noalloc` feature, zero-sized allocations skip the
+    /// call to the allocator entirely and use a dangling-but-aligned
+    /// placeholder pointer instead. The returned `bool` tells the caller
+    /// whether that shortcut was taken, so it can be recorded on the
+    /// `Malloc` for `Drop` instead of re-derived later.
+    unsafe fn alloc_aligned(len: usize) -> (*mut T, bool) {
+        let size = mem::size_of::<T>();
+        if cfg!(feature = "zst_noalloc") && (len == 0 || size == 0) {
+            return (ptr::dangling_mut::<T>(), true);
+        }
+        let bytes = len.checked_mul(size).expect("allocation overflow");
+        let ptr = alloc::memalign(mem::align_of::<T>(), bytes) as *mut T;
+        debug_assert!((ptr as usize).is_multiple_of(mem::align_of::<T>()));
+        (ptr, false)
+    }
+
+    /// The number of elements in the buffer, read directly out of the
+    /// stored fat pointer rather than going through `Deref`.
+    pub fn len(&self) -> usize {
+        (self.ptr as *const [T]).len()
+    }
+
+    /// Whether the buffer has no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 }
 
@@ -43,9 +194,73 @@ impl Malloc<str> {
         let len = libc::strlen(ptr);
         let slice = slice::from_raw_parts(ptr as *mut u8, len);
         str::from_utf8(slice).map(|s| {
-            Malloc { ptr: s as *const str as *mut str }
+            Malloc { ptr: s as *const str as *mut str, dangling: false }
         })
     }
+
+    /// The length of the string in bytes.
+    pub fn len(&self) -> usize {
+        unsafe { (&*self.ptr as &str).len() }
+    }
+
+    /// Whether the string is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// A buffer for the common FFI pattern of a C API writing a NUL-terminated
+/// string into a caller-provided buffer of some maximum length, unlike
+/// `Malloc::from_c_str` which assumes an already-populated pointer.
+///
+/// ```ignore
+/// let mut reader = CStrReader::new(256);
+/// some_c_function(reader.as_mut_ptr(), 256);
+/// let s: Malloc<str> = reader.into_malloc_str().unwrap();
+/// ```
+pub struct CStrReader {
+    buf: Malloc<[u8]>,
+}
+
+impl CStrReader {
+    /// `malloc`s a zero-initialized buffer of `max_len` bytes for a C
+    /// function to write a NUL-terminated string into.
+    ///
+    /// Zeroing (rather than `alloc_uninit`) guarantees a NUL byte at index
+    /// `max_len - 1` even if the C function writes fewer bytes and forgets
+    /// to terminate, so `into_malloc_str`'s `strnlen` can never read past
+    /// what was actually written.
+    pub fn new(max_len: usize) -> CStrReader {
+        CStrReader { buf: Malloc::<[u8]>::zeroed(max_len) }
+    }
+
+    /// Returns a pointer to the buffer to hand to the C function.
+    pub fn as_mut_ptr(&mut self) -> *mut c_char {
+        self.buf.as_mut_ptr() as *mut c_char
+    }
+
+    /// Finds the NUL-terminated string written into the buffer (capped at
+    /// `max_len`), validates it as UTF-8, and shrinks the buffer down to a
+    /// `Malloc<str>` of exactly the written length.
+    pub fn into_malloc_str(self) -> Result<Malloc<str>, Utf8Error> {
+        unsafe {
+            let max_len = self.buf.len();
+            let ptr = self.buf.ptr as *mut u8;
+            let dangling = self.buf.dangling;
+            mem::forget(self.buf);
+            let len = libc::strnlen(ptr as *mut c_char, max_len);
+            let slice = slice::from_raw_parts(ptr, len);
+            match str::from_utf8(slice) {
+                Ok(s) => Ok(Malloc { ptr: s as *const str as *mut str, dangling }),
+                Err(e) => {
+                    // Reconstitute the full-length buffer so it still gets
+                    // `free`'d instead of leaking.
+                    drop(Malloc::from_array(ptr, max_len));
+                    Err(e)
+                }
+            }
+        }
+    }
 }
 
 impl<T: ?Sized> Deref for Malloc<T> {
@@ -56,12 +271,26 @@ impl<T: ?Sized> Deref for Malloc<T> {
     }
 }
 
+/// Mutation through a `Malloc` requires the wrapped memory to be uniquely
+/// owned and writable. The allocating constructors guarantee this; for
+/// the unsafe `from_array`, `from_c_str` and `from_raw`, the caller must.
+impl<T: ?Sized> DerefMut for Malloc<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
 impl<T: ?Sized> Drop for Malloc<T> {
     fn drop(&mut self) {
-        let ptr = self.ptr as *mut c_void;
-        if ptr != DUMMY_PTR {
-            unsafe {
-                libc::free(ptr);
+        // `dangling` is recorded by whichever constructor built this
+        // `Malloc`, rather than re-derived here from the pointer's address
+        // or `T`'s size — both can coincide with a real allocation (e.g. a
+        // genuinely zero-length `from_array` buffer), which would otherwise
+        // make `Drop` silently skip `free` and leak it.
+        unsafe {
+            ptr::drop_in_place(self.ptr);
+            if !self.dangling {
+                alloc::free(self.ptr as *mut c_void);
             }
         }
     }
@@ -72,7 +301,7 @@ mod tests {
     use std::ptr;
     use libc::{c_char, self};
 
-    use super::Malloc;
+    use super::{CStrReader, Malloc};
 
     #[test]
     fn test_null_buf() {
@@ -88,6 +317,18 @@ mod tests {
         assert!(buf.is_none());
     }
 
+    #[test]
+    fn test_from_array_zero_len_real_ptr() {
+        // Unlike `from_array(null, 0)`'s placeholder pointer, this is a
+        // genuine allocation that just happens to be zero-length. `Drop`
+        // must still `free` it rather than treat it as dangling.
+        let buf = unsafe {
+            let ptr = libc::malloc(1) as *mut u32;
+            Malloc::from_array(ptr, 0).unwrap()
+        };
+        assert!(&*buf == []);
+    }
+
     #[test]
     fn test_buf() {
         let buf = unsafe {
@@ -100,6 +341,47 @@ mod tests {
         assert!(&*buf == [1, 2, 3]);
     }
 
+    #[test]
+    fn test_buf_mut() {
+        let mut buf = unsafe {
+            let ptr = libc::malloc(12) as *mut u32;
+            *ptr = 1;
+            *ptr.offset(1) = 2;
+            *ptr.offset(2) = 3;
+            Malloc::from_array(ptr, 3).unwrap()
+        };
+        buf[1] = 42;
+        assert!(&*buf == [1, 42, 3]);
+    }
+
+    #[test]
+    fn test_alloc_uninit() {
+        let mut buf = Malloc::<[u32]>::alloc_uninit(3);
+        buf[0] = 1;
+        buf[1] = 2;
+        buf[2] = 3;
+        assert!(&*buf == [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_zeroed() {
+        let buf = Malloc::<[u32]>::zeroed(3);
+        assert!(&*buf == [0, 0, 0]);
+    }
+
+    #[test]
+    fn test_alloc_uninit_overaligned() {
+        #[repr(align(64))]
+        #[derive(Copy, Clone, PartialEq, Debug)]
+        struct CacheLine(u64);
+
+        let mut buf = Malloc::<[CacheLine]>::alloc_uninit(2);
+        buf[0] = CacheLine(1);
+        buf[1] = CacheLine(2);
+        assert_eq!(buf.as_ptr() as usize % 64, 0);
+        assert!(&*buf == [CacheLine(1), CacheLine(2)]);
+    }
+
     #[test]
     fn test_string() {
         let s = unsafe {
@@ -112,4 +394,103 @@ mod tests {
         };
         assert!(&*s == "hey");
     }
+
+    #[test]
+    fn test_new_runs_destructor() {
+        // A `Vec` is only leak-free if `Drop` actually runs for the
+        // wrapped value before the backing memory is `free`'d.
+        let buf = Malloc::new(vec![1u8, 2, 3]);
+        assert!(&**buf == [1, 2, 3]);
+
+        // Dropping the `Malloc` must release its clone of the `Rc`.
+        let counted = std::rc::Rc::new(());
+        let buf = Malloc::new(counted.clone());
+        assert_eq!(std::rc::Rc::strong_count(&counted), 2);
+        drop(buf);
+        assert_eq!(std::rc::Rc::strong_count(&counted), 1);
+    }
+
+    #[test]
+    fn test_raw_round_trip() {
+        let buf = Malloc::new(42i32);
+        let ptr = buf.into_raw();
+        unsafe {
+            assert!(*ptr == 42);
+            let buf = Malloc::from_raw(ptr);
+            assert!(*buf == 42);
+        }
+    }
+
+    #[test]
+    fn test_new_zero_sized() {
+        // `malloc(0)` is allowed to return null, so a ZST must never
+        // actually go through the allocator.
+        let buf = Malloc::new(());
+        let _: &() = &*buf;
+    }
+
+    #[test]
+    fn test_cstr_reader() {
+        let mut reader = CStrReader::new(8);
+        unsafe {
+            let ptr = reader.as_mut_ptr();
+            *ptr = 'h' as c_char;
+            *ptr.offset(1) = 'i' as c_char;
+            *ptr.offset(2) = '\0' as c_char;
+        }
+        let s = reader.into_malloc_str().unwrap();
+        assert!(&*s == "hi");
+    }
+
+    #[test]
+    fn test_cstr_reader_unterminated() {
+        // With no NUL byte before `max_len`, the string is capped there.
+        let mut reader = CStrReader::new(3);
+        unsafe {
+            let ptr = reader.as_mut_ptr();
+            *ptr = 'h' as c_char;
+            *ptr.offset(1) = 'i' as c_char;
+            *ptr.offset(2) = '!' as c_char;
+        }
+        let s = reader.into_malloc_str().unwrap();
+        assert!(&*s == "hi!");
+    }
+
+    #[test]
+    fn test_cstr_reader_short_write_is_nul_padded() {
+        // A short write with no NUL terminator relies on the buffer being
+        // zero-initialized: the byte right after what was written must
+        // already be `\0`, not uninitialized memory that could extend the
+        // scanned string with stale heap contents.
+        let mut reader = CStrReader::new(8);
+        unsafe {
+            let ptr = reader.as_mut_ptr();
+            *ptr = 'h' as c_char;
+            *ptr.offset(1) = 'i' as c_char;
+        }
+        let s = reader.into_malloc_str().unwrap();
+        assert!(&*s == "hi");
+    }
+
+    #[test]
+    fn test_len() {
+        let buf = Malloc::<[u32]>::zeroed(3);
+        assert_eq!(buf.len(), 3);
+        assert!(!buf.is_empty());
+
+        let empty = Malloc::<[u32]>::zeroed(0);
+        assert_eq!(empty.len(), 0);
+        assert!(empty.is_empty());
+
+        let s = unsafe {
+            let ptr = libc::malloc(4) as *mut c_char;
+            *ptr = 'h' as c_char;
+            *ptr.offset(1) = 'e' as c_char;
+            *ptr.offset(2) = 'y' as c_char;
+            *ptr.offset(3) = '\0' as c_char;
+            Malloc::from_c_str(ptr).unwrap()
+        };
+        assert_eq!(s.len(), 3);
+        assert!(!s.is_empty());
+    }
 }